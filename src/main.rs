@@ -1,12 +1,38 @@
 use clap::{App, Arg};
 use dirs::home_dir;
+use glob::Pattern;
+use regex::Regex;
 use std::ffi::OsString;
 use std::fs::{read_dir, remove_dir, remove_file, set_permissions};
+use std::io::{self, Write};
 use std::path::{Path, PathBuf};
 use std::time::{Duration, SystemTime};
 use std::{env::var_os, fs::symlink_metadata};
 use walkdir::WalkDir;
 
+/// How aggressively to prompt before removing a directory, mirroring `rm -i`
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Interactive {
+    /// Never prompt; remove whatever `--rm` allows
+    Never,
+    /// Prompt a single time before processing any entries
+    Once,
+    /// Prompt before removing each removable directory (empty ones included)
+    Always,
+}
+
+/// Prints `msg`, reads a line from stdin, and returns true if it starts with `y`/`Y`
+fn prompt(msg: &str) -> bool {
+    print!("{}", msg);
+    let _ = io::stdout().flush();
+
+    let mut line = String::new();
+    match io::stdin().read_line(&mut line) {
+        Ok(_) => matches!(line.trim_start().chars().next(), Some('y') | Some('Y')),
+        Err(_) => false,
+    }
+}
+
 fn get_username() -> OsString {
     if cfg!(windows) {
         var_os("USERNAME").expect("Unknown username")
@@ -15,9 +41,27 @@ fn get_username() -> OsString {
     }
 }
 
-fn file_is_old<P: AsRef<Path>>(f: P, use_atime: bool) -> (bool, u64) {
+/// Parses durations of the form `14d`, `6h`, `3w`, or `90s` (bare numbers are seconds)
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let split_at = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(split_at);
+    let num: u64 = num
+        .parse()
+        .map_err(|_| format!("invalid duration {:?}", s))?;
+    let secs = match unit {
+        "" | "s" => num,
+        "m" => num * 60,
+        "h" => num * 60 * 60,
+        "d" => num * 60 * 60 * 24,
+        "w" => num * 60 * 60 * 24 * 7,
+        other => return Err(format!("unknown duration unit {:?} in {:?}", other, s)),
+    };
+    Ok(Duration::from_secs(secs))
+}
+
+fn file_is_old<P: AsRef<Path>>(f: P, use_atime: bool, old: Duration) -> (bool, u64) {
     let f: &Path = f.as_ref();
-    let old = Duration::from_secs(60 * 60 * 24 * 21);
     let now = SystemTime::now();
     if let Ok(md) = symlink_metadata(f) {
         let mda = md.accessed().ok();
@@ -41,6 +85,57 @@ fn file_is_old<P: AsRef<Path>>(f: P, use_atime: bool) -> (bool, u64) {
     }
 }
 
+/// Tallies what happened over the course of a run, so we can print a summary at the end
+/// instead of forcing the user to scroll back through per-directory output
+#[derive(Default)]
+struct Info {
+    examined: u32,
+    skipped: u32,
+    removable: u32,
+    removed: u32,
+    read_failed: u32,
+    remove_failed: u32,
+    bytes_freed: u64,
+}
+
+impl Info {
+    fn failed(&self) -> u32 {
+        self.read_failed + self.remove_failed
+    }
+
+    fn summary(&self) -> String {
+        let mut s = format!(
+            "Scanned {} dirs, removed {}, reclaimed {:.1} GB",
+            self.examined,
+            self.removed,
+            self.bytes_freed as f32 / 1_000_000_000.0
+        );
+        let failed = self.failed();
+        if failed > 0 {
+            s.push_str(&format!(
+                ", {} failure{}",
+                failed,
+                if failed == 1 { "" } else { "s" }
+            ));
+        }
+        s
+    }
+
+    /// Maps what went wrong (if anything) to a process exit code, so cron jobs and CI can
+    /// branch on the kind of failure rather than just "it wasn't zero". A run that finds
+    /// nothing removable is not a failure - that's the steady state once a tmp tree is
+    /// clean - so only actual read/remove errors are reported here.
+    fn exit_code(&self) -> i32 {
+        if self.read_failed > 0 {
+            2
+        } else if self.remove_failed > 0 {
+            3
+        } else {
+            0
+        }
+    }
+}
+
 enum Removable {
     /// A directory is always removable if it is empty
     Always,
@@ -77,11 +172,29 @@ impl Removable {
     }
 }
 
-fn can_be_removed<P: AsRef<Path>>(dir: P, use_atime: bool) -> Result<Removable, std::io::Error> {
+/// True if `path`'s file name matches any of the `--exclude` glob patterns
+fn is_excluded(path: &Path, excludes: &[Pattern]) -> bool {
+    let name = path.file_name().map(|n| n.to_string_lossy());
+    match name {
+        Some(name) => excludes.iter().any(|p| p.matches(&name)),
+        None => false,
+    }
+}
+
+fn can_be_removed<P: AsRef<Path>>(
+    dir: P,
+    use_atime: bool,
+    old: Duration,
+    excludes: &[Pattern],
+) -> Result<Removable, std::io::Error> {
     let dir = dir.as_ref();
 
+    if is_excluded(dir, excludes) {
+        return Ok(Removable::False(dir.to_owned()));
+    }
+
     if dir.is_file() {
-        let (is_old, size) = file_is_old(dir, use_atime);
+        let (is_old, size) = file_is_old(dir, use_atime, old);
         return if is_old {
             Ok(Removable::True(size))
         } else {
@@ -99,9 +212,11 @@ fn can_be_removed<P: AsRef<Path>>(dir: P, use_atime: bool) -> Result<Removable,
     for entry in dirs {
         let entry = entry?.path();
         if entry.is_dir() {
-            remove.and(can_be_removed(entry, use_atime)?);
+            remove.and(can_be_removed(entry, use_atime, old, excludes)?);
+        } else if is_excluded(&entry, excludes) {
+            remove = Removable::False(entry.to_owned());
         } else {
-            let (is_old, size) = file_is_old(&entry, use_atime);
+            let (is_old, size) = file_is_old(&entry, use_atime, old);
             if !is_old {
                 remove = Removable::False(entry.to_owned());
             }
@@ -118,6 +233,55 @@ fn can_be_removed<P: AsRef<Path>>(dir: P, use_atime: bool) -> Result<Removable,
     Ok(remove)
 }
 
+/// Renames `path` to a uniquely-named sibling inside its own parent, then deletes it from
+/// there. On Windows, deletion is scheduled asynchronously by the OS and races with
+/// `contents_first` re-reading the directory it lives in, producing spurious "directory
+/// not empty"/access-denied errors; renaming first (to a name nothing else is watching)
+/// sidesteps that race. The parent is guaranteed to be on the same volume, and we already
+/// hold write permission there since we're recursing through it.
+#[cfg(windows)]
+fn remove_entry(path: &Path, is_dir: bool) -> std::io::Result<()> {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    static RENAME_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    // Windows' MAX_PATH limit doesn't apply to paths using this prefix, but the prefix is
+    // only valid on fully-qualified absolute paths - the caller must pass one in
+    fn extended_length(path: &Path) -> PathBuf {
+        let s = path.as_os_str().to_string_lossy();
+        if s.starts_with(r"\\?\") {
+            path.to_owned()
+        } else {
+            PathBuf::from(format!(r"\\?\{}", s))
+        }
+    }
+
+    // `path` may be relative (e.g. a user-supplied --tmpdir), so canonicalize it first;
+    // the rename target is derived from the now-absolute parent since it doesn't exist
+    // yet and can't be canonicalized itself
+    let path = path.canonicalize()?;
+    let parent = path.parent().expect("entry being removed must have a parent");
+    let n = RENAME_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let tmp = parent.join(format!(".scrubber-del-{}-{}", std::process::id(), n));
+
+    std::fs::rename(extended_length(&path), extended_length(&tmp))?;
+
+    if is_dir {
+        remove_dir(extended_length(&tmp))
+    } else {
+        remove_file(extended_length(&tmp))
+    }
+}
+
+#[cfg(not(windows))]
+fn remove_entry(path: &Path, is_dir: bool) -> std::io::Result<()> {
+    if is_dir {
+        remove_dir(path)
+    } else {
+        remove_file(path)
+    }
+}
+
 /// Recursively clears the read-only flag on every file in this path, and remove them
 fn remove<P: AsRef<Path>>(path: P) -> std::io::Result<()> {
     for entry in WalkDir::new(path)
@@ -131,15 +295,65 @@ fn remove<P: AsRef<Path>>(path: P) -> std::io::Result<()> {
                 let mut perms = md.permissions();
                 perms.set_readonly(false);
                 set_permissions(entry.path(), perms)?;
-                remove_file(entry.path())?;
+                remove_entry(entry.path(), false)?;
             }
         } else if entry.file_type().is_dir() {
-            remove_dir(entry.path())?;
+            remove_entry(entry.path(), true)?;
         }
     }
     Ok(())
 }
 
+/// Walks `path` exactly as `remove` would, but only prints what would happen to each
+/// entry instead of touching the filesystem. At verbosity 1 this stays silent (the caller
+/// already printed the directory-level verdict); at verbosity 2+ it prints a `rm <path>`
+/// line, with size (and age, when the mtime can be read), for every file that would be
+/// deleted, and a `rmdir <path>` line for every directory.
+fn dry_run<P: AsRef<Path>>(path: P, verbosity: u64) {
+    if verbosity < 2 {
+        return;
+    }
+
+    for entry in WalkDir::new(path)
+        .follow_links(false)
+        .same_file_system(true)
+        .contents_first(true)
+    {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                println!("Warning: unable to walk entry: {}", e);
+                continue;
+            }
+        };
+
+        if entry.file_type().is_file() {
+            let md = symlink_metadata(entry.path()).ok();
+            let age_days = md
+                .as_ref()
+                .and_then(|md| md.modified().ok())
+                .and_then(|t| SystemTime::now().duration_since(t).ok())
+                .map(|d| d.as_secs() / (60 * 60 * 24));
+            let size = md.map(|md| md.len()).unwrap_or(0);
+            match age_days {
+                Some(age_days) => println!(
+                    "rm {} (age {}d, {:.1} MB)",
+                    entry.path().display(),
+                    age_days,
+                    size as f32 / 1_000_000.0
+                ),
+                None => println!(
+                    "rm {} ({:.1} MB)",
+                    entry.path().display(),
+                    size as f32 / 1_000_000.0
+                ),
+            }
+        } else if entry.file_type().is_dir() {
+            println!("rmdir {}", entry.path().display());
+        }
+    }
+}
+
 fn main() {
     let matches = App::new("scrubber")
         .version("0.0.1")
@@ -164,11 +378,73 @@ fn main() {
             Arg::with_name("verbose")
                 .short("v")
                 .long("verbose")
-                .help("More verbose output"),
+                .multiple(true)
+                .help("More verbose output. Repeat (-vv) to also list, in a dry run, every file/dir that would be removed"),
+        )
+        .arg(
+            Arg::with_name("interactive")
+                .short("i")
+                .long("interactive")
+                .takes_value(true)
+                .possible_values(&["never", "once", "always"])
+                .default_value("never")
+                .help("Prompt before removing: never, once (before the whole run), or always (before each directory)"),
+        )
+        .arg(
+            Arg::with_name("older-than")
+                .long("older-than")
+                .takes_value(true)
+                .default_value("21d")
+                .help("Only consider files older than this, e.g. 14d, 6h, 3w"),
+        )
+        .arg(
+            Arg::with_name("exclude")
+                .long("exclude")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Glob pattern of files to always keep, regardless of age (can be repeated)"),
+        )
+        .arg(
+            Arg::with_name("name-pattern")
+                .long("name-pattern")
+                .takes_value(true)
+                .default_value(r"^[0-9]{2}$")
+                .help("Regex overriding the default two-digit directory name selector"),
         )
         .get_matches();
 
-    let verbose = matches.is_present("verbose");
+    let verbosity = matches.occurrences_of("verbose");
+    let verbose = verbosity > 0;
+
+    let interactive = match matches.value_of("interactive").unwrap() {
+        "once" => Interactive::Once,
+        "always" => Interactive::Always,
+        _ => Interactive::Never,
+    };
+
+    let old = parse_duration(matches.value_of("older-than").unwrap()).unwrap_or_else(|e| {
+        eprintln!("Invalid --older-than value: {}", e);
+        std::process::exit(1);
+    });
+
+    let excludes: Vec<Pattern> = matches
+        .values_of("exclude")
+        .map(|vals| {
+            vals.map(|v| {
+                Pattern::new(v).unwrap_or_else(|e| {
+                    eprintln!("Invalid --exclude pattern {:?}: {}", v, e);
+                    std::process::exit(1);
+                })
+            })
+            .collect()
+        })
+        .unwrap_or_default();
+
+    let name_pattern = Regex::new(matches.value_of("name-pattern").unwrap()).unwrap_or_else(|e| {
+        eprintln!("Invalid --name-pattern regex: {}", e);
+        std::process::exit(1);
+    });
 
     let mytmp: PathBuf = if let Some(t) = matches.value_of("tmpdir") {
         let p = PathBuf::from(t);
@@ -194,7 +470,7 @@ fn main() {
         }
     };
 
-    let ok_to_remove = matches.is_present("rm");
+    let mut ok_to_remove = matches.is_present("rm");
     let use_atime = !matches.is_present("no-atime");
 
     if !mytmp.exists() {
@@ -202,29 +478,54 @@ fn main() {
         std::process::exit(1);
     }
 
+    if ok_to_remove && interactive == Interactive::Once {
+        ok_to_remove = prompt(&format!(
+            "Remove removable directories under {}? [y/N] ",
+            mytmp.display()
+        ));
+    }
+
+    let mut info = Info::default();
+
     for entry in read_dir(&mytmp).unwrap_or_else(|_| panic!("Unable to read_dir: {:?}", mytmp)) {
         if let Ok(entry) = entry {
             let entry_path = entry.path();
 
-            // only consider directories that seem to be a 2-digit number
+            // only consider directories whose name matches --name-pattern (a 2-digit
+            // number by default)
             let file_name = entry.file_name();
             let name = file_name.to_string_lossy();
-            if !(name.len() == 2
-                && name
-                    .char_indices()
-                    .all(|(idx, chr)| idx < 2 && chr.is_digit(10)))
-            {
+            if !name_pattern.is_match(&name) {
                 if verbose {
                     println!("Will not examine {}", entry_path.display());
                 }
+                info.skipped += 1;
                 continue;
             }
 
-            match can_be_removed(&entry_path, use_atime) {
+            info.examined += 1;
+
+            match can_be_removed(&entry_path, use_atime, old, &excludes) {
                 Ok(Removable::Always) => {
-                    println!("{} is empty and will be removed", entry_path.display());
-                    if let Err(e) = remove_dir(&entry_path) {
-                        println!("Error removing {}: {}", entry_path.display(), e);
+                    println!("{} is empty and can be removed", entry_path.display());
+                    info.removable += 1;
+                    if ok_to_remove {
+                        let should_remove = if interactive == Interactive::Always {
+                            prompt(&format!("remove {}? [y/N] ", entry_path.display()))
+                        } else {
+                            true
+                        };
+                        if should_remove {
+                            match remove_dir(&entry_path) {
+                                Ok(()) => info.removed += 1,
+                                Err(e) => {
+                                    println!("Error removing {}: {}", entry_path.display(), e);
+                                    info.remove_failed += 1;
+                                }
+                            }
+                        }
+                    } else {
+                        dry_run(&entry_path, verbosity);
                     }
                 }
                 Ok(Removable::True(size)) => {
@@ -233,10 +534,27 @@ fn main() {
                         entry_path.display(),
                         size as f32 / 1000000000.0
                     );
+                    info.removable += 1;
                     if ok_to_remove {
-                        if let Err(e) = remove(&entry_path) {
-                            println!("Error removing {}: {}", entry_path.display(), e);
+                        let should_remove = if interactive == Interactive::Always {
+                            prompt(&format!("remove {}? [y/N] ", entry_path.display()))
+                        } else {
+                            true
+                        };
+                        if should_remove {
+                            match remove(&entry_path) {
+                                Ok(()) => {
+                                    info.removed += 1;
+                                    info.bytes_freed += size;
+                                }
+                                Err(e) => {
+                                    println!("Error removing {}: {}", entry_path.display(), e);
+                                    info.remove_failed += 1;
+                                }
+                            }
                         }
+                    } else {
+                        dry_run(&entry_path, verbosity);
                     }
                 }
                 Ok(Removable::False(why)) => {
@@ -246,10 +564,21 @@ fn main() {
                         why.display()
                     );
                 }
-                Err(e) => println!("Unable to read {}: {}", entry_path.display(), e),
+                Err(e) => {
+                    println!("Unable to read {}: {}", entry_path.display(), e);
+                    info.read_failed += 1;
+                }
             }
         } else {
             println!("Warning: Unable to read {:?}", entry.err());
+            info.read_failed += 1;
         }
     }
+
+    println!("{}", info.summary());
+
+    let exit_code = info.exit_code();
+    if exit_code != 0 {
+        std::process::exit(exit_code);
+    }
 }